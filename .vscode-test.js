@@ -61,6 +61,11 @@ const extensions = [
 		label: 'positron-connections',
 		workspaceFolder: path.join(os.tmpdir(), `positron-connections-${Math.floor(Math.random() * 100000)}`),
 		mocha: { timeout: 60_000 }
+	},
+	{
+		label: 'positron-proxy',
+		workspaceFolder: path.join(os.tmpdir(), `positron-proxy-${Math.floor(Math.random() * 100000)}`),
+		mocha: { timeout: 60_000 }
 	}
 	// --- End Positron ---
 ];
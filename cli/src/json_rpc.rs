@@ -16,6 +16,7 @@ use crate::{
 		sync::{Barrier, Receivable},
 	},
 };
+use serde_json::value::RawValue;
 use std::io;
 
 #[derive(Clone)]
@@ -34,6 +35,24 @@ impl Serialization for JsonRpcSerializer {
 	) -> Result<P, crate::util::errors::AnyError> {
 		serde_json::from_slice(b).map_err(|e| InvalidRpcDataError(e.to_string()).into())
 	}
+
+	fn split_batch(&self, b: &[u8]) -> Result<Vec<Vec<u8>>, crate::util::errors::AnyError> {
+		let values: Vec<Box<RawValue>> =
+			serde_json::from_slice(b).map_err(|e| InvalidRpcDataError(e.to_string()))?;
+		Ok(values.iter().map(|v| v.get().as_bytes().to_vec()).collect())
+	}
+
+	fn join_batch(&self, messages: Vec<Vec<u8>>) -> Vec<u8> {
+		let mut v = vec![b'['];
+		for (i, message) in messages.iter().enumerate() {
+			if i > 0 {
+				v.push(b',');
+			}
+			v.extend_from_slice(message.strip_suffix(b"\n").unwrap_or(message));
+		}
+		v.extend_from_slice(b"]\n");
+		v
+	}
 }
 
 /// Creates a new RPC Builder that serializes to JSON.
@@ -47,11 +66,15 @@ pub async fn start_json_rpc<C: Send + Sync + 'static, S: Clone>(
 	dispatcher: rpc::RpcDispatcher<JsonRpcSerializer, C>,
 	read: impl AsyncRead + Unpin,
 	mut write: impl AsyncWrite + Unpin,
-	mut msg_rx: impl Receivable<Vec<u8>>,
+	msg_rx: impl Receivable<Vec<u8>>,
 	mut shutdown_rx: Barrier<S>,
 ) -> io::Result<Option<S>> {
 	let (write_tx, mut write_rx) = mpsc::channel::<Vec<u8>>(8);
 	let mut read = BufReader::new(read);
+	let connection = dispatcher.new_connection();
+	// declared after the connection so that it's dropped first, closing the
+	// channel of any calls that were sent through it
+	let mut msg_rx = msg_rx;
 
 	let mut read_buf = String::new();
 	let shutdown_fut = shutdown_rx.wait();
@@ -69,7 +92,7 @@ pub async fn start_json_rpc<C: Send + Sync + 'static, S: Clone>(
 			n = read.read_line(&mut read_buf) => {
 				let r = match n {
 					Ok(0) => return Ok(None),
-					Ok(n) => dispatcher.dispatch(read_buf[..n].as_bytes()),
+					Ok(n) => dispatcher.dispatch(&connection, read_buf[..n].as_bytes()),
 					Err(e) => return Err(e)
 				};
 
@@ -4,7 +4,7 @@
  *--------------------------------------------------------------------------------------------*/
 
 use bytes::Buf;
-use serde::de::DeserializeOwned;
+use serde::de::{DeserializeOwned, IgnoredAny};
 use tokio::{
 	io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
 	pin,
@@ -32,6 +32,49 @@ impl Serialization for MsgPackSerializer {
 	fn deserialize<P: serde::de::DeserializeOwned>(&self, b: &[u8]) -> Result<P, AnyError> {
 		rmp_serde::from_slice(b).map_err(|e| InvalidRpcDataError(e.to_string()).into())
 	}
+
+	fn split_batch(&self, b: &[u8]) -> Result<Vec<Vec<u8>>, AnyError> {
+		// array headers, per https://github.com/msgpack/msgpack/blob/master/spec.md#array-format-family
+		let (len, mut start) = match b {
+			[m @ 0x90..=0x9f, ..] => ((m & 0x0f) as usize, 1),
+			[0xdc, a, b, ..] => (u16::from_be_bytes([*a, *b]) as usize, 3),
+			[0xdd, a, b, c, d, ..] => (u32::from_be_bytes([*a, *b, *c, *d]) as usize, 5),
+			_ => return Err(InvalidRpcDataError("expected a msgpack array".to_string()).into()),
+		};
+
+		// each message takes at least a byte, so don't trust larger lengths
+		let mut messages = Vec::with_capacity(len.min(b.len()));
+		for _ in 0..len {
+			let mut cursor = Cursor::new(&b[start..]);
+			rmp_serde::decode::from_read::<_, IgnoredAny>(&mut cursor)
+				.map_err(|e| InvalidRpcDataError(e.to_string()))?;
+			let end = start + cursor.position() as usize;
+			messages.push(b[start..end].to_vec());
+			start = end;
+		}
+
+		Ok(messages)
+	}
+
+	fn join_batch(&self, messages: Vec<Vec<u8>>) -> Vec<u8> {
+		let mut v = Vec::with_capacity(messages.iter().map(|m| m.len()).sum::<usize>() + 5);
+		match messages.len() {
+			n if n < 16 => v.push(0x90 | n as u8),
+			n if n <= u16::MAX as usize => {
+				v.push(0xdc);
+				v.extend_from_slice(&(n as u16).to_be_bytes());
+			}
+			n => {
+				v.push(0xdd);
+				v.extend_from_slice(&(n as u32).to_be_bytes());
+			}
+		}
+
+		for message in messages {
+			v.extend_from_slice(&message);
+		}
+		v
+	}
 }
 
 pub type MsgPackCaller = rpc::RpcCaller<MsgPackSerializer>;
@@ -53,12 +96,16 @@ pub async fn start_msgpack_rpc<
 	dispatcher: rpc::RpcDispatcher<S, C>,
 	mut read: Read,
 	mut write: Write,
-	mut msg_rx: impl Receivable<Vec<u8>>,
+	msg_rx: impl Receivable<Vec<u8>>,
 	mut shutdown_rx: Barrier<X>,
 ) -> io::Result<(Option<X>, Read, Write)> {
 	let (write_tx, mut write_rx) = mpsc::channel::<Vec<u8>>(8);
 	let mut decoder = MsgPackCodec::new();
 	let mut decoder_buf = bytes::BytesMut::new();
+	let connection = dispatcher.new_connection();
+	// declared after the connection so that it's dropped first, closing the
+	// channel of any calls that were sent through it
+	let mut msg_rx = msg_rx;
 
 	let shutdown_fut = shutdown_rx.wait();
 	pin!(shutdown_fut);
@@ -69,7 +116,7 @@ pub async fn start_msgpack_rpc<
 				r?;
 
 				while let Some(frame) = decoder.decode(&mut decoder_buf)? {
-					match dispatcher.dispatch_with_partial(&frame.vec, frame.obj) {
+					match dispatcher.dispatch_with_partial(&connection, &frame.vec, frame.obj) {
 						MaybeSync::Sync(Some(v)) => {
 							let _ = write_tx.send(v).await;
 						},
@@ -192,4 +239,30 @@ mod tests {
 			Msg { x: 2 }
 		);
 	}
+
+	#[test]
+	fn test_batch() {
+		let s = MsgPackSerializer {};
+		for n in [0, 3, 20] {
+			let messages: Vec<Vec<u8>> = (0..n).map(|x| s.serialize(Msg { x })).collect();
+			let batch = s.join_batch(messages.clone());
+			assert_eq!(s.split_batch(&batch).unwrap(), messages);
+		}
+		assert!(s.split_batch(&[0xdd, 0xff, 0xff, 0xff, 0xff]).is_err());
+
+		let mut c = MsgPackCodec::<rpc::PartialIncoming>::new();
+		let mut buf = bytes::BytesMut::from(&s.join_batch(vec![s.serialize(Msg { x: 1 })])[..]);
+		let partial = c.decode(&mut buf).unwrap().expect("expected batch").obj;
+		assert!(partial.batch);
+
+		buf.extend_from_slice(&s.serialize(rpc::FullRequest {
+			id: Some(1),
+			method: "m",
+			params: Msg { x: 1 },
+		}));
+		let partial = c.decode(&mut buf).unwrap().expect("expected request").obj;
+		assert_eq!(partial.id, Some(1));
+		assert_eq!(partial.method.as_deref(), Some("m"));
+		assert!(!partial.batch);
+	}
 }
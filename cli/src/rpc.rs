@@ -5,26 +5,36 @@
 
 use std::{
 	collections::HashMap,
-	future,
+	fmt, future,
 	sync::{
-		atomic::{AtomicU32, Ordering},
+		atomic::{AtomicU32, AtomicU64, Ordering},
 		Arc, Mutex,
 	},
+	time::{Duration, Instant},
 };
 
 use crate::log;
 use futures::{future::BoxFuture, Future, FutureExt};
-use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde::{
+	de::{DeserializeOwned, IgnoredAny, MapAccess, SeqAccess, Visitor},
+	Deserialize, Deserializer, Serialize,
+};
 use tokio::{
 	io::{AsyncReadExt, AsyncWriteExt, DuplexStream, WriteHalf},
 	sync::{mpsc, oneshot},
 };
+use tokio_util::sync::CancellationToken;
 
 use crate::util::errors::AnyError;
 
 pub type SyncMethod = Arc<dyn Send + Sync + Fn(Option<u32>, &[u8]) -> Option<Vec<u8>>>;
 pub type AsyncMethod =
 	Arc<dyn Send + Sync + Fn(Option<u32>, &[u8]) -> BoxFuture<'static, Option<Vec<u8>>>>;
+pub type CancellableMethod = Arc<
+	dyn Send
+		+ Sync
+		+ Fn(Option<u32>, &[u8], CancellationToken) -> BoxFuture<'static, Option<Vec<u8>>>,
+>;
 pub type Duplex = Arc<
 	dyn Send
 		+ Sync
@@ -34,6 +44,7 @@ pub type Duplex = Arc<
 pub enum Method {
 	Sync(SyncMethod),
 	Async(AsyncMethod),
+	Cancellable(CancellableMethod),
 	Duplex(Duplex),
 }
 
@@ -42,6 +53,11 @@ pub enum Method {
 pub trait Serialization: Send + Sync + 'static {
 	fn serialize(&self, value: impl Serialize) -> Vec<u8>;
 	fn deserialize<P: DeserializeOwned>(&self, b: &[u8]) -> Result<P, AnyError>;
+	/// Splits a batch, which is an array of messages, into the individual
+	/// serialized messages it contains.
+	fn split_batch(&self, b: &[u8]) -> Result<Vec<Vec<u8>>, AnyError>;
+	/// Joins serialized messages into a single batch.
+	fn join_batch(&self, messages: Vec<Vec<u8>>) -> Vec<u8>;
 }
 
 /// RPC is a basic, transport-agnostic builder for RPC methods. You can
@@ -49,7 +65,7 @@ pub trait Serialization: Send + Sync + 'static {
 pub struct RpcBuilder<S> {
 	serializer: Arc<S>,
 	methods: HashMap<&'static str, Method>,
-	calls: Arc<Mutex<HashMap<u32, DispatchMethod>>>,
+	calls: Arc<Mutex<HashMap<u32, PendingCall>>>,
 }
 
 impl<S: Serialization> RpcBuilder<S> {
@@ -79,6 +95,8 @@ impl<S: Serialization> RpcBuilder<S> {
 			serializer: self.serializer,
 			methods: self.methods,
 			calls: self.calls,
+			timeouts: HashMap::new(),
+			stats: HashMap::new(),
 		}
 	}
 }
@@ -87,7 +105,9 @@ pub struct RpcMethodBuilder<S, C> {
 	context: Arc<C>,
 	serializer: Arc<S>,
 	methods: HashMap<&'static str, Method>,
-	calls: Arc<Mutex<HashMap<u32, DispatchMethod>>>,
+	calls: Arc<Mutex<HashMap<u32, PendingCall>>>,
+	timeouts: HashMap<&'static str, Duration>,
+	stats: HashMap<&'static str, Arc<MethodCounters>>,
 }
 
 #[derive(Serialize)]
@@ -97,6 +117,24 @@ struct DuplexStreamStarted {
 }
 
 impl<S: Serialization, C: Send + Sync + 'static> RpcMethodBuilder<S, C> {
+	/// Sets a timeout for calls to an async or duplex method, which must
+	/// already be registered. Calls that take longer are dropped and answered
+	/// with an error.
+	pub fn set_timeout(&mut self, method_name: &'static str, timeout: Duration) {
+		match self.methods.get(method_name) {
+			Some(Method::Sync(_)) => panic!("Cannot time out sync method: {}", method_name),
+			Some(_) => {}
+			None => panic!("Method not registered: {}", method_name),
+		}
+
+		self.timeouts.insert(method_name, timeout);
+	}
+
+	/// Gets the counters used to record stats for the method.
+	fn counters(&mut self, method_name: &'static str) -> Arc<MethodCounters> {
+		self.stats.entry(method_name).or_default().clone()
+	}
+
 	/// Registers a synchronous rpc call that returns its result directly.
 	pub fn register_sync<P, R, F>(&mut self, method_name: &'static str, callback: F)
 	where
@@ -110,40 +148,36 @@ impl<S: Serialization, C: Send + Sync + 'static> RpcMethodBuilder<S, C> {
 
 		let serial = self.serializer.clone();
 		let context = self.context.clone();
+		let counters = self.counters(method_name);
 		self.methods.insert(
 			method_name,
 			Method::Sync(Arc::new(move |id, body| {
 				let param = match serial.deserialize::<RequestParams<P>>(body) {
 					Ok(p) => p,
-					Err(err) => {
-						return id.map(|id| {
-							serial.serialize(ErrorResponse {
-								id,
-								error: ResponseError {
-									code: 0,
-									message: format!("{:?}", err),
-								},
-							})
-						})
-					}
+					Err(err) => return error_response(&*serial, &counters, id, 0, err),
 				};
 
 				match callback(param.params, &context) {
 					Ok(result) => id.map(|id| serial.serialize(&SuccessResponse { id, result })),
-					Err(err) => id.map(|id| {
-						serial.serialize(ErrorResponse {
-							id,
-							error: ResponseError {
-								code: -1,
-								message: format!("{:?}", err),
-							},
-						})
-					}),
+					Err(err) => error_response(&*serial, &counters, id, -1, err),
 				}
 			})),
 		);
 	}
 
+	/// Registers a notification, a call that the client doesn't expect a
+	/// response to. If a client does send an ID, it gets an empty result.
+	pub fn register_notification<P, F>(&mut self, method_name: &'static str, callback: F)
+	where
+		P: DeserializeOwned,
+		F: Fn(P, &C) + Send + Sync + 'static,
+	{
+		self.register_sync(method_name, move |p, c| {
+			callback(p, c);
+			Ok(())
+		});
+	}
+
 	/// Registers an async rpc call that returns a Future.
 	pub fn register_async<P, R, Fut, F>(&mut self, method_name: &'static str, callback: F)
 	where
@@ -154,42 +188,84 @@ impl<S: Serialization, C: Send + Sync + 'static> RpcMethodBuilder<S, C> {
 	{
 		let serial = self.serializer.clone();
 		let context = self.context.clone();
+		let counters = self.counters(method_name);
 		self.methods.insert(
 			method_name,
 			Method::Async(Arc::new(move |id, body| {
 				let param = match serial.deserialize::<RequestParams<P>>(body) {
 					Ok(p) => p,
 					Err(err) => {
-						return future::ready(id.map(|id| {
-							serial.serialize(ErrorResponse {
-								id,
-								error: ResponseError {
-									code: 0,
-									message: format!("{:?}", err),
-								},
-							})
-						}))
-						.boxed();
+						return future::ready(error_response(&*serial, &counters, id, 0, err))
+							.boxed();
 					}
 				};
 
 				let callback = callback.clone();
 				let serial = serial.clone();
 				let context = context.clone();
+				let counters = counters.clone();
 				let fut = async move {
 					match callback(param.params, context).await {
 						Ok(result) => {
 							id.map(|id| serial.serialize(&SuccessResponse { id, result }))
 						}
-						Err(err) => id.map(|id| {
-							serial.serialize(ErrorResponse {
-								id,
-								error: ResponseError {
-									code: -1,
-									message: format!("{:?}", err),
-								},
-							})
-						}),
+						Err(err) => error_response(&*serial, &counters, id, -1, err),
+					}
+				};
+
+				fut.boxed()
+			})),
+		);
+	}
+
+	/// Registers an async rpc call that returns a Future and receives a token
+	/// that's cancelled when the client sends `$cancel` for the call, or when
+	/// its connection closes. Handlers should check the token and stop their
+	/// work; the future is never dropped because of it. An error returned after
+	/// the token was cancelled is sent with `ERROR_CODE_CANCELLED`.
+	///
+	/// The token is also cancelled once the call completes, so it can be used
+	/// to stop spawned work that's tied to the call.
+	pub fn register_async_cancellable<P, R, Fut, F>(
+		&mut self,
+		method_name: &'static str,
+		callback: F,
+	) where
+		P: DeserializeOwned + Send + 'static,
+		R: Serialize + Send + Sync + 'static,
+		Fut: Future<Output = Result<R, AnyError>> + Send,
+		F: (Fn(P, Arc<C>, CancellationToken) -> Fut) + Clone + Send + Sync + 'static,
+	{
+		let serial = self.serializer.clone();
+		let context = self.context.clone();
+		let counters = self.counters(method_name);
+		self.methods.insert(
+			method_name,
+			Method::Cancellable(Arc::new(move |id, body, token| {
+				let param = match serial.deserialize::<RequestParams<P>>(body) {
+					Ok(p) => p,
+					Err(err) => {
+						return future::ready(error_response(&*serial, &counters, id, 0, err))
+							.boxed();
+					}
+				};
+
+				let callback = callback.clone();
+				let serial = serial.clone();
+				let context = context.clone();
+				let counters = counters.clone();
+				let fut = async move {
+					match callback(param.params, context, token.clone()).await {
+						Ok(result) => {
+							id.map(|id| serial.serialize(&SuccessResponse { id, result }))
+						}
+						Err(err) => {
+							let code = match token.is_cancelled() {
+								true => ERROR_CODE_CANCELLED,
+								false => -1,
+							};
+							error_response(&*serial, &counters, id, code, err)
+						}
 					}
 				};
 
@@ -213,6 +289,7 @@ impl<S: Serialization, C: Send + Sync + 'static> RpcMethodBuilder<S, C> {
 	{
 		let serial = self.serializer.clone();
 		let context = self.context.clone();
+		let counters = self.counters(method_name);
 		self.methods.insert(
 			method_name,
 			Method::Duplex(Arc::new(move |id, body| {
@@ -221,16 +298,7 @@ impl<S: Serialization, C: Send + Sync + 'static> RpcMethodBuilder<S, C> {
 					Err(err) => {
 						return (
 							None,
-							future::ready(id.map(|id| {
-								serial.serialize(ErrorResponse {
-									id,
-									error: ResponseError {
-										code: 0,
-										message: format!("{:?}", err),
-									},
-								})
-							}))
-							.boxed(),
+							future::ready(error_response(&*serial, &counters, id, 0, err)).boxed(),
 						);
 					}
 				};
@@ -238,10 +306,13 @@ impl<S: Serialization, C: Send + Sync + 'static> RpcMethodBuilder<S, C> {
 				let callback = callback.clone();
 				let serial = serial.clone();
 				let context = context.clone();
+				let counters = counters.clone();
 
 				let mut dto = StreamDto {
 					req_id: id.unwrap_or(0),
 					streams: Vec::with_capacity(streams),
+					progress: None,
+					progress_tx: None,
 				};
 				let mut servers = Vec::with_capacity(streams);
 
@@ -254,15 +325,77 @@ impl<S: Serialization, C: Send + Sync + 'static> RpcMethodBuilder<S, C> {
 				let fut = async move {
 					match callback(servers, param.params, context).await {
 						Ok(r) => id.map(|id| serial.serialize(&SuccessResponse { id, result: r })),
-						Err(err) => id.map(|id| {
-							serial.serialize(ErrorResponse {
-								id,
-								error: ResponseError {
-									code: -1,
-									message: format!("{:?}", err),
-								},
-							})
-						}),
+						Err(err) => error_response(&*serial, &counters, id, -1, err),
+					}
+				};
+
+				(Some(dto), fut.boxed())
+			})),
+		);
+	}
+
+	/// Registers an async rpc call that can report progress to the client
+	/// before it completes. Progress is sent as `$progress` notifications that
+	/// reference the call's request ID, and the response always follows the
+	/// last of them. Progress sent after the call completes is dropped.
+	///
+	/// These calls are dispatched like duplex calls without any streams, so
+	/// transports forward their messages when calling `register_stream`. Like
+	/// other duplex calls, they can't be made in a batch.
+	pub fn register_async_with_progress<P, R, Pr, Fut, F>(
+		&mut self,
+		method_name: &'static str,
+		callback: F,
+	) where
+		P: DeserializeOwned + Send + 'static,
+		R: Serialize + Send + Sync + 'static,
+		Pr: Serialize + 'static,
+		Fut: Future<Output = Result<R, AnyError>> + Send,
+		F: (Fn(P, Arc<C>, ProgressSender<S, Pr>) -> Fut) + Clone + Send + Sync + 'static,
+	{
+		let serial = self.serializer.clone();
+		let context = self.context.clone();
+		let counters = self.counters(method_name);
+		self.methods.insert(
+			method_name,
+			Method::Duplex(Arc::new(move |id, body| {
+				let param = match serial.deserialize::<RequestParams<P>>(body) {
+					Ok(p) => p,
+					Err(err) => {
+						return (
+							None,
+							future::ready(error_response(&*serial, &counters, id, 0, err)).boxed(),
+						);
+					}
+				};
+
+				let callback = callback.clone();
+				let serial = serial.clone();
+				let context = context.clone();
+				let counters = counters.clone();
+
+				let (tx, rx) = mpsc::unbounded_channel();
+				let progress = ProgressSender {
+					id,
+					serializer: serial.clone(),
+					tx: Arc::new(Mutex::new(Some(tx.clone()))),
+					_marker: std::marker::PhantomData,
+				};
+				let close = CloseProgress(progress.tx.clone());
+
+				let dto = StreamDto {
+					req_id: id.unwrap_or(0),
+					streams: Vec::new(),
+					progress: Some(rx),
+					progress_tx: Some(tx),
+				};
+
+				let fut = async move {
+					// dropped once the call completes, or if its future is dropped
+					let _close = close;
+					match callback(param.params, context, progress).await {
+						Ok(r) => id.map(|id| serial.serialize(&SuccessResponse { id, result: r })),
+						Err(err) => error_response(&*serial, &counters, id, -1, err),
 					}
 				};
 
@@ -285,31 +418,95 @@ impl<S: Serialization, C: Send + Sync + 'static> RpcMethodBuilder<S, C> {
 		});
 
 		let s2 = streams.clone();
-		self.register_sync(METHOD_STREAM_DATA, move |m: StreamDataIncomingParams, _| {
+		self.register_notification(METHOD_STREAM_DATA, move |m: StreamDataIncomingParams, _| {
 			s2.write(m.stream, m.segment);
-			Ok(())
 		});
 
+		// create the counters for the stats method first, so that it's
+		// included in its own stats
+		self.counters(METHOD_STATS);
+		let stats = Arc::new(RpcStats {
+			methods: self.stats.clone(),
+		});
+		let s3 = stats.clone();
+		self.register_sync(METHOD_STATS, move |_: (), _| Ok(s3.snapshot()));
+
 		RpcDispatcher {
+			instrumentation: Arc::new(Instrumentation {
+				log: log.clone(),
+				serializer: self.serializer.clone(),
+				stats,
+			}),
 			log,
 			context: self.context,
 			calls: self.calls,
 			serializer: self.serializer,
 			methods: Arc::new(self.methods),
 			streams,
+			timeouts: Arc::new(self.timeouts),
 		}
 	}
 }
 
 type DispatchMethod = Box<dyn Send + Sync + FnOnce(Outcome)>;
 
+/// An outbound call that's waiting for its response.
+struct PendingCall {
+	/// Channel the call was sent on. Once it's closed, the call can no longer
+	/// be answered.
+	sender: mpsc::UnboundedSender<Vec<u8>>,
+	dispatch: DispatchMethod,
+}
+
+/// Sends progress for a call registered with `register_async_with_progress`.
+pub struct ProgressSender<S, Pr> {
+	id: Option<u32>,
+	serializer: Arc<S>,
+	/// Channel to the client, which is taken once the call completes.
+	tx: Arc<Mutex<Option<mpsc::UnboundedSender<Vec<u8>>>>>,
+	_marker: std::marker::PhantomData<fn(Pr)>,
+}
+
+impl<S: Serialization, Pr: Serialize> ProgressSender<S, Pr> {
+	/// Sends a progress update to the client. Nothing is sent if the call was
+	/// made as a notification, since there's no request ID to report against,
+	/// or if the call already completed.
+	pub fn send(&self, progress: Pr) {
+		if let Some(id) = self.id {
+			let body = self.serializer.serialize(&FullRequest {
+				id: None,
+				method: METHOD_PROGRESS,
+				params: ProgressParams {
+					for_request_id: id,
+					progress,
+				},
+			});
+			// the lock is held while sending, so progress can't be sent after
+			// the response once `CloseProgress` has taken the channel
+			if let Some(tx) = &*self.tx.lock().unwrap() {
+				tx.send(body).ok();
+			}
+		}
+	}
+}
+
+/// Closes the `ProgressSender` of a call when dropped, so that progress from
+/// work the call spawned can't follow its response.
+struct CloseProgress(Arc<Mutex<Option<mpsc::UnboundedSender<Vec<u8>>>>>);
+
+impl Drop for CloseProgress {
+	fn drop(&mut self) {
+		self.0.lock().unwrap_or_else(|e| e.into_inner()).take();
+	}
+}
+
 /// Dispatcher returned from a Builder that provides a transport-agnostic way to
 /// deserialize and dispatch RPC calls. This structure may get more advanced as
 /// time goes on...
 #[derive(Clone)]
 pub struct RpcCaller<S: Serialization> {
 	serializer: Arc<S>,
-	calls: Arc<Mutex<HashMap<u32, DispatchMethod>>>,
+	calls: Arc<Mutex<HashMap<u32, PendingCall>>>,
 	sender: mpsc::UnboundedSender<Vec<u8>>,
 }
 
@@ -361,20 +558,25 @@ impl<S: Serialization> RpcCaller<S> {
 		let serializer = self.serializer.clone();
 		self.calls.lock().unwrap().insert(
 			id,
-			Box::new(move |body| {
-				match body {
-					Outcome::Error(e) => tx.send(Err(e)).ok(),
-					Outcome::Success(r) => match serializer.deserialize::<SuccessResponse<R>>(&r) {
-						Ok(r) => tx.send(Ok(r.result)).ok(),
-						Err(err) => tx
-							.send(Err(ResponseError {
-								code: 0,
-								message: err.to_string(),
-							}))
-							.ok(),
-					},
-				};
-			}),
+			PendingCall {
+				sender: self.sender.clone(),
+				dispatch: Box::new(move |body| {
+					match body {
+						Outcome::Error(e) => tx.send(Err(e)).ok(),
+						Outcome::Success(r) => {
+							match serializer.deserialize::<SuccessResponse<R>>(&r) {
+								Ok(r) => tx.send(Ok(r.result)).ok(),
+								Err(err) => tx
+									.send(Err(ResponseError {
+										code: 0,
+										message: err.to_string(),
+									}))
+									.ok(),
+							}
+						}
+					};
+				}),
+			},
 		);
 
 		rx
@@ -390,8 +592,10 @@ pub struct RpcDispatcher<S, C> {
 	context: Arc<C>,
 	serializer: Arc<S>,
 	methods: Arc<HashMap<&'static str, Method>>,
-	calls: Arc<Mutex<HashMap<u32, DispatchMethod>>>,
+	calls: Arc<Mutex<HashMap<u32, PendingCall>>>,
 	streams: Streams,
+	timeouts: Arc<HashMap<&'static str, Duration>>,
+	instrumentation: Arc<Instrumentation<S>>,
 }
 
 static MESSAGE_ID_COUNTER: AtomicU32 = AtomicU32::new(0);
@@ -406,9 +610,9 @@ impl<S: Serialization, C: Send + Sync> RpcDispatcher<S, C> {
 	///
 	/// The future or return result will be optional bytes that should be sent
 	/// back to the socket.
-	pub fn dispatch(&self, body: &[u8]) -> MaybeSync {
+	pub fn dispatch(&self, connection: &RpcConnection, body: &[u8]) -> MaybeSync {
 		match self.serializer.deserialize::<PartialIncoming>(body) {
-			Ok(partial) => self.dispatch_with_partial(body, partial),
+			Ok(partial) => self.dispatch_with_partial(connection, body, partial),
 			Err(_err) => {
 				warning!(self.log, "Failed to deserialize request, hex: {:X?}", body);
 				MaybeSync::Sync(None)
@@ -417,15 +621,73 @@ impl<S: Serialization, C: Send + Sync> RpcDispatcher<S, C> {
 	}
 
 	/// Like dispatch, but allows passing an existing PartialIncoming.
-	pub fn dispatch_with_partial(&self, body: &[u8], partial: PartialIncoming) -> MaybeSync {
+	pub fn dispatch_with_partial(
+		&self,
+		connection: &RpcConnection,
+		body: &[u8],
+		partial: PartialIncoming,
+	) -> MaybeSync {
+		if partial.batch {
+			return self.dispatch_batch(connection, body);
+		}
+
 		let id = partial.id;
 
 		if let Some(method_name) = partial.method {
-			let method = self.methods.get(method_name.as_str());
+			// calls can only be cancelled from their own connection, so this is
+			// handled here rather than registered as a method
+			if method_name == METHOD_CANCEL {
+				if let Ok(p) = self
+					.serializer
+					.deserialize::<RequestParams<CancelParams>>(body)
+				{
+					connection.cancellations.cancel(p.params.id);
+				}
+				return MaybeSync::Sync(id.map(|id| {
+					self.serializer
+						.serialize(&SuccessResponse { id, result: () })
+				}));
+			}
+
+			let method = self.methods.get_key_value(method_name.as_str());
 			match method {
-				Some(Method::Sync(callback)) => MaybeSync::Sync(callback(id, body)),
-				Some(Method::Async(callback)) => MaybeSync::Future(callback(id, body)),
-				Some(Method::Duplex(callback)) => MaybeSync::Stream(callback(id, body)),
+				Some((name, Method::Sync(callback))) => {
+					let started = Instant::now();
+					let r = callback(id, body);
+					self.instrumentation.record_call(name, started, true);
+					MaybeSync::Sync(r)
+				}
+				Some((name, Method::Async(callback))) => {
+					MaybeSync::Future(self.make_instrumented(name, id, callback(id, body), true))
+				}
+				Some((name, Method::Cancellable(callback))) => {
+					let token = CancellationToken::new();
+					let in_flight = connection.cancellations.track(id, token.clone());
+					let fut = callback(id, body, token);
+					let fut = async move {
+						let _in_flight = in_flight;
+						fut.await
+					};
+					MaybeSync::Future(self.make_instrumented(name, id, fut.boxed(), true))
+				}
+				Some((name, Method::Duplex(callback))) => {
+					let (mut dto, fut) = callback(id, body);
+					let fut = self.make_instrumented(name, id, fut, false);
+					match dto.as_mut().and_then(|d| d.progress_tx.take()) {
+						// the response, including a timeout error, goes through the same
+						// channel as progress so that it can't overtake progress sent before it
+						Some(tx) => {
+							let fut = async move {
+								if let Some(r) = fut.await {
+									tx.send(r).ok();
+								}
+								None
+							};
+							MaybeSync::Stream((dto, fut.boxed()))
+						}
+						None => MaybeSync::Stream((dto, fut)),
+					}
+				}
 				None => MaybeSync::Sync(id.map(|id| {
 					self.serializer.serialize(ErrorResponse {
 						id,
@@ -437,24 +699,199 @@ impl<S: Serialization, C: Send + Sync> RpcDispatcher<S, C> {
 				})),
 			}
 		} else if let Some(err) = partial.error {
-			if let Some(cb) = self.calls.lock().unwrap().remove(&id.unwrap()) {
-				cb(Outcome::Error(err));
+			// errors without an ID answer messages the other side couldn't parse,
+			// so there's no call to fail
+			let id = match id {
+				Some(id) => id,
+				None => {
+					warning!(self.log, "Peer rejected a message: {}", err.message);
+					return MaybeSync::Sync(None);
+				}
+			};
+			if let Some(call) = self.calls.lock().unwrap().remove(&id) {
+				(call.dispatch)(Outcome::Error(err));
 			}
 			MaybeSync::Sync(None)
 		} else {
-			if let Some(cb) = self.calls.lock().unwrap().remove(&id.unwrap()) {
-				cb(Outcome::Success(body.to_vec()));
+			// a response must reference the call it answers
+			let id = match id {
+				Some(id) => id,
+				None => {
+					warning!(self.log, "Received a response without an ID, ignoring");
+					return MaybeSync::Sync(None);
+				}
+			};
+			if let Some(call) = self.calls.lock().unwrap().remove(&id) {
+				(call.dispatch)(Outcome::Success(body.to_vec()));
 			}
 			MaybeSync::Sync(None)
 		}
 	}
 
+	/// Dispatches each message in a batch in order. Responses are returned as a
+	/// single batch, in the same order as the calls they answer. Notifications
+	/// and responses to our own calls don't produce an entry.
+	fn dispatch_batch(&self, connection: &RpcConnection, body: &[u8]) -> MaybeSync {
+		let messages = match self.serializer.split_batch(body) {
+			Ok(m) => m,
+			Err(e) => {
+				warning!(self.log, "Failed to split batch: {}", e);
+				return MaybeSync::Sync(None);
+			}
+		};
+
+		// like JSON-RPC, an empty batch gets a single error rather than a batch
+		if messages.is_empty() {
+			warning!(self.log, "Received an empty batch");
+			return MaybeSync::Sync(Some(self.invalid_request("Empty batch")));
+		}
+
+		let mut all_sync = true;
+		let mut results = Vec::with_capacity(messages.len());
+		for message in messages {
+			let partial = match self.serializer.deserialize::<PartialIncoming>(&message) {
+				Ok(p) if p.is_message() => p,
+				_ => {
+					warning!(self.log, "Invalid message in batch, hex: {:X?}", message);
+					let response = self.invalid_request("Invalid message in batch");
+					results.push(future::ready(Some(response)).boxed());
+					continue;
+				}
+			};
+
+			let is_duplex = partial
+				.method
+				.as_deref()
+				.and_then(|m| self.methods.get(m))
+				.map(|m| matches!(m, Method::Duplex(_)))
+				.unwrap_or(false);
+
+			let result = if is_duplex {
+				// streams can't be set up from within a batch, since each gets its own
+				// `streams_started` message that must precede the response
+				future::ready(partial.id.map(|id| {
+					self.serializer.serialize(ErrorResponse {
+						id,
+						error: ResponseError {
+							code: -1,
+							message: "Duplex methods cannot be called in a batch".to_string(),
+						},
+					})
+				}))
+				.boxed()
+			} else {
+				match self.dispatch_with_partial(connection, &message, partial) {
+					MaybeSync::Sync(r) => future::ready(r).boxed(),
+					MaybeSync::Future(fut) | MaybeSync::Stream((_, fut)) => {
+						all_sync = false;
+						fut
+					}
+				}
+			};
+
+			results.push(result);
+		}
+
+		let serial = self.serializer.clone();
+		let join = move |responses: Vec<Option<Vec<u8>>>| {
+			let responses: Vec<_> = responses.into_iter().flatten().collect();
+			if responses.is_empty() {
+				None
+			} else {
+				Some(serial.join_batch(responses))
+			}
+		};
+
+		if all_sync {
+			let responses = results
+				.into_iter()
+				.map(|r| r.now_or_never().flatten())
+				.collect();
+			MaybeSync::Sync(join(responses))
+		} else {
+			MaybeSync::Future(async move { join(futures::future::join_all(results).await) }.boxed())
+		}
+	}
+
+	/// Serializes an error for a message whose call is unknown, so like
+	/// JSON-RPC, it has no ID.
+	fn invalid_request(&self, message: &str) -> Vec<u8> {
+		self.serializer.serialize(UnknownIdErrorResponse {
+			id: None,
+			error: ResponseError {
+				code: ERROR_CODE_INVALID_REQUEST,
+				message: message.to_string(),
+			},
+		})
+	}
+
+	/// Wraps the future of an incoming call to enforce the method's timeout,
+	/// if any, and record stats about the call once it finishes.
+	fn make_instrumented(
+		&self,
+		method_name: &'static str,
+		id: Option<u32>,
+		fut: BoxFuture<'static, Option<Vec<u8>>>,
+		log_slow: bool,
+	) -> BoxFuture<'static, Option<Vec<u8>>> {
+		let timeout = self.timeouts.get(method_name).copied();
+		let instrumentation = self.instrumentation.clone();
+		async move {
+			let started = Instant::now();
+			let r = match timeout {
+				None => fut.await,
+				Some(t) => match tokio::time::timeout(t, fut).await {
+					Ok(r) => r,
+					Err(_) => {
+						instrumentation.stats.record_error(method_name);
+						id.map(|id| {
+							instrumentation.serializer.serialize(ErrorResponse {
+								id,
+								error: ResponseError {
+									code: ERROR_CODE_TIMED_OUT,
+									message: format!("Call timed out after {:?}", t),
+								},
+							})
+						})
+					}
+				},
+			};
+
+			instrumentation.record_call(method_name, started, log_slow);
+			r
+		}
+		.boxed()
+	}
+
+	/// Creates the state for a new connection. Transports should create one
+	/// for each connection they serve, pass it to `dispatch`, and drop it once
+	/// the connection closes.
+	pub fn new_connection(&self) -> RpcConnection {
+		RpcConnection {
+			cancellations: Cancellations::default(),
+			calls: self.calls.clone(),
+		}
+	}
+
 	/// Registers a stream call returned from dispatch().
 	pub async fn register_stream(
 		&self,
 		write_tx: mpsc::Sender<impl 'static + From<Vec<u8>> + Send>,
 		dto: StreamDto,
 	) {
+		// calls that report progress have no streams to announce, only
+		// messages to forward
+		if let Some(mut progress) = dto.progress {
+			tokio::spawn(async move {
+				while let Some(m) = progress.recv().await {
+					if write_tx.send(m.into()).await.is_err() {
+						return;
+					}
+				}
+			});
+			return;
+		}
+
 		let r = write_tx
 			.send(
 				self.serializer
@@ -528,6 +965,216 @@ impl<S: Serialization, C: Send + Sync> RpcDispatcher<S, C> {
 	}
 }
 
+/// Records stats about calls and logs slow ones. It's shared with the futures
+/// of async calls, which can't borrow the dispatcher.
+struct Instrumentation<S> {
+	log: log::Logger,
+	serializer: Arc<S>,
+	stats: Arc<RpcStats>,
+}
+
+impl<S: Serialization> Instrumentation<S> {
+	/// Records stats for a finished call, and logs it if it was slow. Errors
+	/// are recorded separately by the method, which knows whether it failed.
+	fn record_call(&self, method_name: &'static str, started: Instant, log_slow: bool) {
+		let elapsed = started.elapsed();
+		self.stats.record(method_name, elapsed);
+
+		if log_slow && elapsed >= SLOW_CALL_THRESHOLD {
+			warning!(
+				self.log,
+				"Slow RPC call to {} took {:?}",
+				method_name,
+				elapsed
+			);
+		}
+	}
+}
+
+/// Upper bounds of the buckets in the latency histograms of `RpcStats`. Calls
+/// slower than the last bound go into a final overflow bucket.
+const LATENCY_BUCKETS_MS: [u64; 8] = [1, 5, 10, 50, 100, 500, 1000, 5000];
+
+#[derive(Default)]
+struct MethodCounters {
+	calls: AtomicU64,
+	errors: AtomicU64,
+	latency: [AtomicU64; LATENCY_BUCKETS_MS.len() + 1],
+}
+
+impl MethodCounters {
+	pub fn record_error(&self) {
+		self.errors.fetch_add(1, Ordering::Relaxed);
+	}
+}
+
+/// Records a failed call to a method, and serializes the error response to
+/// send back if the call has an ID.
+fn error_response<S: Serialization>(
+	serial: &S,
+	counters: &MethodCounters,
+	id: Option<u32>,
+	code: i32,
+	err: impl fmt::Debug,
+) -> Option<Vec<u8>> {
+	counters.record_error();
+	id.map(|id| {
+		serial.serialize(ErrorResponse {
+			id,
+			error: ResponseError {
+				code,
+				message: format!("{:?}", err),
+			},
+		})
+	})
+}
+
+/// Per-method call counters. The set of methods is fixed when the dispatcher
+/// is built, so recording a call doesn't need a lock.
+struct RpcStats {
+	methods: HashMap<&'static str, Arc<MethodCounters>>,
+}
+
+impl RpcStats {
+	pub fn record(&self, method_name: &str, elapsed: Duration) {
+		let counters = match self.methods.get(method_name) {
+			Some(c) => c,
+			None => return,
+		};
+
+		counters.calls.fetch_add(1, Ordering::Relaxed);
+
+		let ms = elapsed.as_millis() as u64;
+		let bucket = LATENCY_BUCKETS_MS
+			.iter()
+			.position(|b| ms < *b)
+			.unwrap_or(LATENCY_BUCKETS_MS.len());
+		counters.latency[bucket].fetch_add(1, Ordering::Relaxed);
+	}
+
+	pub fn record_error(&self, method_name: &str) {
+		if let Some(c) = self.methods.get(method_name) {
+			c.record_error();
+		}
+	}
+
+	pub fn snapshot(&self) -> StatsResponse {
+		let mut methods: Vec<MethodStats> = self
+			.methods
+			.iter()
+			.filter(|(_, c)| c.calls.load(Ordering::Relaxed) > 0)
+			.map(|(name, c)| MethodStats {
+				method: name.to_string(),
+				calls: c.calls.load(Ordering::Relaxed),
+				errors: c.errors.load(Ordering::Relaxed),
+				latency_counts: c
+					.latency
+					.iter()
+					.map(|l| l.load(Ordering::Relaxed))
+					.collect(),
+			})
+			.collect();
+		methods.sort_by(|a, b| a.method.cmp(&b.method));
+
+		StatsResponse {
+			latency_buckets_ms: LATENCY_BUCKETS_MS.to_vec(),
+			methods,
+		}
+	}
+}
+
+/// State of a single connection served by a dispatcher, created with
+/// `RpcDispatcher::new_connection`. A dispatcher may be shared between several
+/// connections, whose clients each number their calls independently.
+pub struct RpcConnection {
+	cancellations: Cancellations,
+	calls: Arc<Mutex<HashMap<u32, PendingCall>>>,
+}
+
+impl Drop for RpcConnection {
+	fn drop(&mut self) {
+		self.cancellations.cancel_all();
+
+		// Fail outbound calls whose channel closed along with the connection,
+		// so callers aren't left waiting forever. Calls sent on other
+		// connections are left alone.
+		// This may run while unwinding from a panic that poisoned the lock, and
+		// panicking again would abort. The map is still usable in that case.
+		let closed: Vec<PendingCall> = {
+			let mut calls = self.calls.lock().unwrap_or_else(|e| e.into_inner());
+			let ids: Vec<u32> = calls
+				.iter()
+				.filter(|(_, c)| c.sender.is_closed())
+				.map(|(id, _)| *id)
+				.collect();
+			ids.iter().filter_map(|id| calls.remove(id)).collect()
+		};
+
+		for call in closed {
+			(call.dispatch)(Outcome::Error(ResponseError {
+				code: ERROR_CODE_CONNECTION_CLOSED,
+				message: "Connection closed before a response was received".to_string(),
+			}));
+		}
+	}
+}
+
+/// Cancellation tokens for incoming calls that are still in flight, keyed
+/// by their request ID.
+#[derive(Default)]
+struct Cancellations {
+	map: Arc<std::sync::Mutex<HashMap<u32, CancellationToken>>>,
+}
+
+impl Cancellations {
+	pub fn track(&self, id: Option<u32>, token: CancellationToken) -> InFlightCall {
+		if let Some(id) = id {
+			self.map.lock().unwrap().insert(id, token.clone());
+		}
+
+		InFlightCall {
+			id,
+			token,
+			map: self.map.clone(),
+		}
+	}
+
+	pub fn cancel(&self, id: u32) {
+		if let Some(token) = self.map.lock().unwrap().get(&id) {
+			token.cancel();
+		}
+	}
+
+	pub fn cancel_all(&self) {
+		let map = self.map.lock().unwrap_or_else(|e| e.into_inner());
+		for token in map.values() {
+			token.cancel();
+		}
+	}
+}
+
+/// Guard held by the future of an in-flight call. When dropped, either
+/// because the call finished or because the transport dropped the future,
+/// it cancels the call's token and stops tracking it.
+struct InFlightCall {
+	id: Option<u32>,
+	token: CancellationToken,
+	map: Arc<std::sync::Mutex<HashMap<u32, CancellationToken>>>,
+}
+
+impl Drop for InFlightCall {
+	fn drop(&mut self) {
+		self.token.cancel();
+
+		if let Some(id) = self.id {
+			self.map
+				.lock()
+				.unwrap_or_else(|e| e.into_inner())
+				.remove(&id);
+		}
+	}
+}
+
 struct StreamRec {
 	write: Option<WriteHalf<DuplexStream>>,
 	q: Vec<Vec<u8>>,
@@ -633,17 +1280,108 @@ async fn write_loop(
 const METHOD_STREAMS_STARTED: &str = "streams_started";
 const METHOD_STREAM_DATA: &str = "stream_data";
 const METHOD_STREAM_ENDED: &str = "stream_ended";
+const METHOD_PROGRESS: &str = "$progress";
+
+/// Built-in method that returns a `StatsResponse` describing the calls the
+/// dispatcher has handled.
+pub const METHOD_STATS: &str = "$stats";
+
+/// How long a sync or async call may take before it's logged as slow. Duplex
+/// calls are expected to be long-lived and aren't logged.
+const SLOW_CALL_THRESHOLD: Duration = Duration::from_secs(5);
+
+/// Built-in method a client can call (usually as a notification) to cancel an
+/// in-flight call it previously made to a method registered with
+/// `register_async_cancellable`.
+pub const METHOD_CANCEL: &str = "$cancel";
+
+/// Error code returned for cancellable calls that fail after being cancelled.
+/// This matches the `RequestCancelled` code used by the language server protocol.
+pub const ERROR_CODE_CANCELLED: i32 = -32800;
+
+/// Error code given to outbound calls whose transport closed before a
+/// response was received.
+pub const ERROR_CODE_CONNECTION_CLOSED: i32 = -32000;
+
+/// Error code returned for messages in a batch that couldn't be parsed. This
+/// matches the `Invalid Request` code used by JSON-RPC.
+pub const ERROR_CODE_INVALID_REQUEST: i32 = -32600;
+
+/// Error code returned for calls that exceeded their method's timeout.
+pub const ERROR_CODE_TIMED_OUT: i32 = -32001;
 
 #[allow(dead_code)] // false positive
 trait AssertIsSync: Sync {}
 impl<S: Serialization, C: Send + Sync> AssertIsSync for RpcDispatcher<S, C> {}
 
 /// Approximate shape that is used to determine what kind of data is incoming.
-#[derive(Deserialize, Debug)]
+#[derive(Debug, Default)]
 pub struct PartialIncoming {
 	pub id: Option<u32>,
 	pub method: Option<String>,
 	pub error: Option<ResponseError>,
+	/// Whether the incoming data is a batch of messages. If so, the other
+	/// fields are unset.
+	pub batch: bool,
+}
+
+impl PartialIncoming {
+	/// Whether this is a single call or response, rather than a batch or a
+	/// message with none of the fields that identify either.
+	fn is_message(&self) -> bool {
+		!self.batch && (self.method.is_some() || self.error.is_some() || self.id.is_some())
+	}
+}
+
+impl<'de> Deserialize<'de> for PartialIncoming {
+	fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+		deserializer.deserialize_any(PartialIncomingVisitor)
+	}
+}
+
+#[derive(Deserialize)]
+#[serde(field_identifier, rename_all = "lowercase")]
+enum PartialIncomingField {
+	Id,
+	Method,
+	Error,
+	#[serde(other)]
+	Other,
+}
+
+struct PartialIncomingVisitor;
+
+impl<'de> Visitor<'de> for PartialIncomingVisitor {
+	type Value = PartialIncoming;
+
+	fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+		formatter.write_str("an rpc message or a batch of messages")
+	}
+
+	fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+		let mut partial = PartialIncoming::default();
+		while let Some(field) = map.next_key()? {
+			match field {
+				PartialIncomingField::Id => partial.id = map.next_value()?,
+				PartialIncomingField::Method => partial.method = map.next_value()?,
+				PartialIncomingField::Error => partial.error = map.next_value()?,
+				PartialIncomingField::Other => {
+					map.next_value::<IgnoredAny>()?;
+				}
+			}
+		}
+
+		Ok(partial)
+	}
+
+	fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+		while seq.next_element::<IgnoredAny>()?.is_some() {}
+
+		Ok(PartialIncoming {
+			batch: true,
+			..Default::default()
+		})
+	}
 }
 
 #[derive(Deserialize)]
@@ -665,6 +1403,33 @@ struct StreamEndedParams {
 	pub stream: u32,
 }
 
+#[derive(Serialize)]
+struct ProgressParams<P> {
+	pub for_request_id: u32,
+	pub progress: P,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StatsResponse {
+	/// Upper bounds of the latency buckets, in milliseconds. Each method's
+	/// `latency_counts` has one more entry, for calls slower than the last bound.
+	pub latency_buckets_ms: Vec<u64>,
+	pub methods: Vec<MethodStats>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MethodStats {
+	pub method: String,
+	pub calls: u64,
+	pub errors: u64,
+	pub latency_counts: Vec<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CancelParams {
+	pub id: u32,
+}
+
 #[derive(Serialize)]
 pub struct FullRequest<M: AsRef<str>, P> {
 	pub id: Option<u32>,
@@ -689,6 +1454,13 @@ struct ErrorResponse {
 	pub error: ResponseError,
 }
 
+/// Error response to a message whose request ID couldn't be determined.
+#[derive(Serialize)]
+struct UnknownIdErrorResponse {
+	pub id: Option<u32>,
+	pub error: ResponseError,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ResponseError {
 	pub code: i32,
@@ -703,6 +1475,8 @@ enum Outcome {
 pub struct StreamDto {
 	req_id: u32,
 	streams: Vec<(u32, DuplexStream)>,
+	progress: Option<mpsc::UnboundedReceiver<Vec<u8>>>,
+	progress_tx: Option<mpsc::UnboundedSender<Vec<u8>>>,
 }
 
 pub enum MaybeSync {
@@ -714,6 +1488,388 @@ pub enum MaybeSync {
 #[cfg(test)]
 mod tests {
 	use super::*;
+	use crate::json_rpc::{new_json_rpc, JsonRpcSerializer};
+
+	fn request(id: Option<u32>, method: &str, params: impl Serialize) -> Vec<u8> {
+		JsonRpcSerializer {}.serialize(&FullRequest { id, method, params })
+	}
+
+	#[tokio::test]
+	async fn test_cancel_async_call() {
+		let mut rpc = new_json_rpc().methods(());
+		rpc.register_async_cancellable("wait", |_: (), _, token| async move {
+			token.cancelled().await;
+			Err::<(), _>(AnyError::from(crate::util::errors::InvalidRpcDataError(
+				"stopped".to_string(),
+			)))
+		});
+		rpc.register_async("pending", |_: (), _| {
+			futures::future::pending::<Result<(), AnyError>>()
+		});
+		let dispatcher = rpc.build(log::Logger::test());
+		let connection = dispatcher.new_connection();
+		let other = dispatcher.new_connection();
+
+		let fut = match dispatcher.dispatch(&connection, &request(Some(1), "wait", ())) {
+			MaybeSync::Future(fut) => fut,
+			_ => panic!("expected a future"),
+		};
+		let mut pending = match dispatcher.dispatch(&connection, &request(Some(2), "pending", ())) {
+			MaybeSync::Future(fut) => fut,
+			_ => panic!("expected a future"),
+		};
+		assert!(connection
+			.cancellations
+			.map
+			.lock()
+			.unwrap()
+			.contains_key(&1));
+
+		// calls can't be cancelled from another connection
+		let cancel = request(None, METHOD_CANCEL, CancelParams { id: 1 });
+		assert!(matches!(
+			dispatcher.dispatch(&other, &cancel),
+			MaybeSync::Sync(None)
+		));
+		assert!(!connection.cancellations.map.lock().unwrap()[&1].is_cancelled());
+
+		dispatcher.dispatch(&connection, &cancel);
+		let response: ErrorResponse = JsonRpcSerializer {}
+			.deserialize(&fut.await.expect("expected a response"))
+			.unwrap();
+		assert_eq!(response.id, 1);
+		assert_eq!(response.error.code, ERROR_CODE_CANCELLED);
+		assert!(connection.cancellations.map.lock().unwrap().is_empty());
+
+		// methods that aren't cancellable keep running
+		dispatcher.dispatch(
+			&connection,
+			&request(None, METHOD_CANCEL, CancelParams { id: 2 }),
+		);
+		assert!(futures::poll!(&mut pending).is_pending());
+	}
+
+	#[tokio::test]
+	async fn test_cancellation_token_on_close() {
+		let (tx, mut rx) = mpsc::unbounded_channel();
+		let mut rpc = new_json_rpc().methods(());
+		rpc.register_async_cancellable("watch", move |_: (), _, token| {
+			let tx = tx.clone();
+			tokio::spawn(async move {
+				token.cancelled().await;
+				let _ = tx.send(());
+			});
+			futures::future::pending::<Result<(), AnyError>>()
+		});
+		let dispatcher = rpc.build(log::Logger::test());
+		let connection = dispatcher.new_connection();
+
+		let mut fut = match dispatcher.dispatch(&connection, &request(Some(1), "watch", ())) {
+			MaybeSync::Future(fut) => fut,
+			_ => panic!("expected a future"),
+		};
+		assert!(futures::poll!(&mut fut).is_pending());
+		drop(connection);
+
+		assert!(rx.recv().await.is_some());
+		assert!(futures::poll!(&mut fut).is_pending());
+	}
+
+	#[tokio::test]
+	async fn test_pending_calls_fail_on_close() {
+		let (tx1, rx1) = mpsc::unbounded_channel();
+		let (tx2, _rx2) = mpsc::unbounded_channel();
+		let mut rpc = new_json_rpc();
+		let caller1 = rpc.get_caller(tx1);
+		let caller2 = rpc.get_caller(tx2);
+		let dispatcher = rpc.methods(()).build(log::Logger::test());
+		let connection1 = dispatcher.new_connection();
+		let connection2 = dispatcher.new_connection();
+
+		let mut response1 = caller1.call::<_, _, ()>("echo", ());
+		let mut response2 = caller2.call::<_, _, ()>("echo", ());
+
+		// calls whose channel is still open aren't failed
+		drop(connection2);
+		assert!(response1.try_recv().is_err());
+
+		drop(rx1);
+		drop(connection1);
+
+		let err = response1.await.unwrap().unwrap_err();
+		assert_eq!(err.code, ERROR_CODE_CONNECTION_CLOSED);
+		assert!(response2.try_recv().is_err());
+		assert_eq!(dispatcher.calls.lock().unwrap().len(), 1);
+	}
+
+	#[tokio::test]
+	async fn test_close_after_poisoned_calls() {
+		let (tx, rx) = mpsc::unbounded_channel();
+		let mut rpc = new_json_rpc();
+		let caller = rpc.get_caller(tx);
+		let dispatcher = rpc.methods(()).build(log::Logger::test());
+		let connection = dispatcher.new_connection();
+
+		let response = caller.call::<_, _, ()>("echo", ());
+		let calls = dispatcher.calls.clone();
+		std::thread::spawn(move || {
+			let _lock = calls.lock().unwrap();
+			panic!("poison the lock");
+		})
+		.join()
+		.unwrap_err();
+
+		drop(rx);
+		drop(connection);
+		assert_eq!(
+			response.await.unwrap().unwrap_err().code,
+			ERROR_CODE_CONNECTION_CLOSED
+		);
+	}
+
+	#[tokio::test]
+	async fn test_dispatch_batch() {
+		let (tx, mut rx) = mpsc::unbounded_channel();
+		let mut rpc = new_json_rpc().methods(());
+		rpc.register_sync("double", |n: u32, _| Ok(n * 2));
+		rpc.register_async("triple", |n: u32, _| async move { Ok(n * 3) });
+		rpc.register_notification("notify", move |n: u32, _| {
+			tx.send(n).unwrap();
+		});
+		let dispatcher = rpc.build(log::Logger::test());
+		let connection = dispatcher.new_connection();
+
+		let serial = JsonRpcSerializer {};
+		let batch = serial.join_batch(vec![
+			request(Some(1), "triple", 1),
+			request(None, "notify", 2),
+			b"42".to_vec(),
+			request(Some(2), "double", 3),
+		]);
+
+		let response = match dispatcher.dispatch(&connection, &batch) {
+			MaybeSync::Future(fut) => fut.await.expect("expected a response"),
+			_ => panic!("expected a future"),
+		};
+
+		let responses: Vec<serde_json::Value> = serial.deserialize(&response).unwrap();
+		assert_eq!(responses.len(), 3);
+		assert_eq!(
+			(&responses[0]["id"], &responses[0]["result"]),
+			(&1.into(), &3.into())
+		);
+		assert!(responses[1]["id"].is_null());
+		assert_eq!(responses[1]["error"]["code"], ERROR_CODE_INVALID_REQUEST);
+		assert_eq!(
+			(&responses[2]["id"], &responses[2]["result"]),
+			(&2.into(), &6.into())
+		);
+
+		// errors without an ID are logged, rather than failing a call
+		assert!(matches!(
+			dispatcher.dispatch(&connection, &serde_json::to_vec(&responses[1]).unwrap()),
+			MaybeSync::Sync(None)
+		));
+		assert_eq!(rx.recv().await, Some(2));
+	}
+
+	#[tokio::test]
+	async fn test_dispatch_invalid_batch() {
+		let dispatcher = new_json_rpc().methods(()).build(log::Logger::test());
+		let connection = dispatcher.new_connection();
+		let serial = JsonRpcSerializer {};
+
+		// messages that are neither calls nor responses are invalid
+		let response = match dispatcher.dispatch(&connection, b"[{}]") {
+			MaybeSync::Sync(Some(r)) => r,
+			_ => panic!("expected a sync response"),
+		};
+		let responses: Vec<serde_json::Value> = serial.deserialize(&response).unwrap();
+		assert_eq!(responses.len(), 1);
+		assert!(responses[0]["id"].is_null());
+		assert_eq!(responses[0]["error"]["code"], ERROR_CODE_INVALID_REQUEST);
+
+		// and are ignored outside of a batch
+		assert!(matches!(
+			dispatcher.dispatch(&connection, b"{}"),
+			MaybeSync::Sync(None)
+		));
+
+		let response = match dispatcher.dispatch(&connection, b"[]") {
+			MaybeSync::Sync(Some(r)) => r,
+			_ => panic!("expected a sync response"),
+		};
+		let response: serde_json::Value = serial.deserialize(&response).unwrap();
+		assert!(response["id"].is_null());
+		assert_eq!(response["error"]["code"], ERROR_CODE_INVALID_REQUEST);
+	}
+
+	#[tokio::test]
+	async fn test_progress() {
+		let (senders_tx, mut senders_rx) = mpsc::unbounded_channel();
+		let mut rpc = new_json_rpc().methods(());
+		rpc.register_async_with_progress("count", move |n: u32, _, progress| {
+			let senders_tx = senders_tx.clone();
+			async move {
+				for i in 0..n {
+					progress.send(i);
+				}
+				senders_tx.send(progress).ok();
+				Ok("done")
+			}
+		});
+		let dispatcher = rpc.build(log::Logger::test());
+		let connection = dispatcher.new_connection();
+		let serial = JsonRpcSerializer {};
+
+		let (dto, fut) = match dispatcher.dispatch(&connection, &request(Some(1), "count", 3)) {
+			MaybeSync::Stream((Some(dto), fut)) => (dto, fut),
+			_ => panic!("expected a stream"),
+		};
+		let (write_tx, mut write_rx) = mpsc::channel::<Vec<u8>>(8);
+		dispatcher.register_stream(write_tx, dto).await;
+		assert!(fut.await.is_none());
+
+		// a sender that outlives its call no longer sends anything
+		senders_rx.recv().await.unwrap().send(3);
+
+		let mut messages: Vec<serde_json::Value> = vec![];
+		while let Some(m) = write_rx.recv().await {
+			messages.push(serial.deserialize(&m).unwrap());
+		}
+		assert_eq!(messages.len(), 4);
+		for (i, m) in messages[..3].iter().enumerate() {
+			assert_eq!(m["method"], METHOD_PROGRESS);
+			assert_eq!(m["params"]["for_request_id"], 1);
+			assert_eq!(m["params"]["progress"], i);
+		}
+		assert_eq!(
+			(&messages[3]["id"], &messages[3]["result"]),
+			(&1.into(), &"done".into())
+		);
+	}
+
+	#[tokio::test]
+	async fn test_duplex_without_streams() {
+		let mut rpc = new_json_rpc().methods(());
+		rpc.register_duplex("open", 0, |_, _: (), _| async { Ok(()) });
+		let dispatcher = rpc.build(log::Logger::test());
+		let connection = dispatcher.new_connection();
+
+		let dto = match dispatcher.dispatch(&connection, &request(Some(1), "open", ())) {
+			MaybeSync::Stream((Some(dto), _)) => dto,
+			_ => panic!("expected a stream"),
+		};
+		let (write_tx, mut write_rx) = mpsc::channel::<Vec<u8>>(8);
+		dispatcher.register_stream(write_tx, dto).await;
+
+		let started: serde_json::Value = JsonRpcSerializer {}
+			.deserialize(&write_rx.recv().await.unwrap())
+			.unwrap();
+		assert_eq!(started["method"], METHOD_STREAMS_STARTED);
+		assert_eq!(started["params"]["for_request_id"], 1);
+	}
+
+	#[tokio::test]
+	async fn test_method_timeout() {
+		let mut rpc = new_json_rpc().methods(());
+		rpc.register_async("wait", |_: (), _| {
+			futures::future::pending::<Result<(), AnyError>>()
+		});
+		rpc.register_async_with_progress("slow", |_: (), _, progress| async move {
+			progress.send(1);
+			futures::future::pending::<Result<(), AnyError>>().await
+		});
+		rpc.set_timeout("wait", Duration::from_millis(10));
+		rpc.set_timeout("slow", Duration::from_millis(10));
+		let dispatcher = rpc.build(log::Logger::test());
+		let connection = dispatcher.new_connection();
+		let serial = JsonRpcSerializer {};
+
+		let fut = match dispatcher.dispatch(&connection, &request(Some(1), "wait", ())) {
+			MaybeSync::Future(fut) => fut,
+			_ => panic!("expected a future"),
+		};
+
+		let response: ErrorResponse = serial
+			.deserialize(&fut.await.expect("expected a response"))
+			.unwrap();
+		assert_eq!(response.error.code, ERROR_CODE_TIMED_OUT);
+
+		// the timeout of a call with progress is sent after its progress
+		let (dto, fut) = match dispatcher.dispatch(&connection, &request(Some(2), "slow", ())) {
+			MaybeSync::Stream((Some(dto), fut)) => (dto, fut),
+			_ => panic!("expected a stream"),
+		};
+		let (write_tx, mut write_rx) = mpsc::channel::<Vec<u8>>(8);
+		dispatcher.register_stream(write_tx, dto).await;
+		assert!(fut.await.is_none());
+
+		let progress: serde_json::Value =
+			serial.deserialize(&write_rx.recv().await.unwrap()).unwrap();
+		assert_eq!(progress["method"], METHOD_PROGRESS);
+		let response: ErrorResponse = serial.deserialize(&write_rx.recv().await.unwrap()).unwrap();
+		assert_eq!(response.id, 2);
+		assert_eq!(response.error.code, ERROR_CODE_TIMED_OUT);
+
+		let stats = dispatcher.instrumentation.stats.snapshot();
+		assert!(stats.methods.iter().all(|m| m.calls == 1 && m.errors == 1));
+	}
+
+	#[test]
+	#[should_panic(expected = "Cannot time out sync method")]
+	fn test_timeout_on_sync_method() {
+		let mut rpc = new_json_rpc().methods(());
+		rpc.register_sync("now", |_: (), _| Ok(()));
+		rpc.set_timeout("now", Duration::from_secs(1));
+	}
+
+	#[tokio::test]
+	async fn test_stats() {
+		let mut rpc = new_json_rpc().methods(());
+		rpc.register_sync("check", |ok: bool, _| match ok {
+			true => Ok(()),
+			false => Err(AnyError::from(crate::util::errors::InvalidRpcDataError(
+				"not ok".to_string(),
+			))),
+		});
+		rpc.register_async_with_progress("fail", |_: (), _, _: ProgressSender<_, ()>| async {
+			Err::<(), _>(AnyError::from(crate::util::errors::InvalidRpcDataError(
+				"failed".to_string(),
+			)))
+		});
+		let dispatcher = rpc.build(log::Logger::test());
+		let connection = dispatcher.new_connection();
+
+		dispatcher.dispatch(&connection, &request(Some(1), "check", true));
+		dispatcher.dispatch(&connection, &request(Some(2), "check", false));
+		dispatcher.dispatch(&connection, &request(None, "check", true));
+		match dispatcher.dispatch(&connection, &request(Some(3), "fail", ())) {
+			MaybeSync::Stream((_, fut)) => assert!(fut.await.is_none()),
+			_ => panic!("expected a stream"),
+		}
+
+		let response = match dispatcher.dispatch(&connection, &request(Some(4), METHOD_STATS, ())) {
+			MaybeSync::Sync(Some(r)) => r,
+			_ => panic!("expected a sync response"),
+		};
+		let stats: SuccessResponse<StatsResponse> =
+			JsonRpcSerializer {}.deserialize(&response).unwrap();
+
+		assert_eq!(stats.result.methods.len(), 2);
+		let check = &stats.result.methods[0];
+		assert_eq!(check.method, "check");
+		assert_eq!(check.calls, 3);
+		assert_eq!(check.errors, 1);
+		assert_eq!(check.latency_counts.iter().sum::<u64>(), 3);
+		assert_eq!(
+			check.latency_counts.len(),
+			stats.result.latency_buckets_ms.len() + 1
+		);
+
+		let fail = &stats.result.methods[1];
+		assert_eq!((fail.calls, fail.errors), (1, 1));
+	}
 
 	#[tokio::test]
 	async fn test_remove() {
@@ -7,7 +7,7 @@ use crate::constants::{CONTROL_PORT, PRODUCT_NAME_LONG};
 use crate::log;
 use crate::msgpack_rpc::{new_msgpack_rpc, start_msgpack_rpc, MsgPackCodec, MsgPackSerializer};
 use crate::options::Quality;
-use crate::rpc::{MaybeSync, RpcBuilder, RpcCaller, RpcDispatcher};
+use crate::rpc::{MaybeSync, ProgressSender, RpcBuilder, RpcCaller, RpcDispatcher};
 use crate::self_update::SelfUpdate;
 use crate::state::LauncherPaths;
 use crate::tunnels::protocol::{HttpRequestParams, PortPrivacy, METHOD_CHALLENGE_ISSUE};
@@ -20,7 +20,7 @@ use crate::util::errors::{
 use crate::util::http::{
 	DelegatedHttpRequest, DelegatedSimpleHttp, FallbackSimpleHttp, ReqwestSimpleHttp,
 };
-use crate::util::io::SilentCopyProgress;
+use crate::util::io::ReportCopyProgress;
 use crate::util::is_integrated_cli;
 use crate::util::machine::kill_pid;
 use crate::util::os::os_release;
@@ -37,10 +37,11 @@ use tokio::net::TcpStream;
 use tokio::pin;
 use tokio::process::{ChildStderr, ChildStdin};
 use tokio_util::codec::Decoder;
+use tokio_util::sync::CancellationToken;
 
 use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, DuplexStream};
 use tokio::sync::{mpsc, Mutex};
 
@@ -58,8 +59,8 @@ use super::protocol::{
 	ForwardResult, FsReadDirEntry, FsReadDirResponse, FsRenameRequest, FsSinglePathRequest,
 	FsStatResponse, GetEnvResponse, GetHostnameResponse, HttpBodyParams, HttpHeadersParams,
 	NetConnectRequest, ServeParams, ServerLog, ServerMessageParams, SpawnParams, SpawnResult,
-	SysKillRequest, SysKillResponse, ToClientRequest, UnforwardParams, UpdateParams, UpdateResult,
-	VersionResponse, METHOD_CHALLENGE_VERIFY,
+	SysKillRequest, SysKillResponse, ToClientRequest, UnforwardParams, UpdateParams,
+	UpdateProgress, UpdateResult, VersionResponse, METHOD_CHALLENGE_VERIFY,
 };
 use super::server_bridge::ServerBridge;
 use super::server_multiplexer::ServerMultiplexer;
@@ -68,6 +69,11 @@ use super::socket_signal::{
 	ClientMessageDecoder, ServerMessageDestination, ServerMessageSink, SocketSignal,
 };
 
+/// How long a `callserverhttp` call may wait for the code server to respond.
+const CALL_SERVER_HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long a `forward` or `unforward` call may wait for the tunnel service.
+const PORT_FORWARDING_TIMEOUT: Duration = Duration::from_secs(60);
+
 type HttpRequestsMap = Arc<std::sync::Mutex<HashMap<u32, DelegatedHttpRequest>>>;
 type CodeServerCell = Arc<Mutex<Option<SocketCodeServer>>>;
 
@@ -426,8 +432,8 @@ fn make_socket_rpc(
 		ensure_auth(&c.auth_state)?;
 		handle_serve(c, params).await
 	});
-	rpc.register_async("update", |p: UpdateParams, c| async move {
-		handle_update(&c.http, &c.log, &c.did_update, &p).await
+	rpc.register_async_with_progress("update", |p: UpdateParams, c, progress| async move {
+		handle_update(&c.http, &c.log, &c.did_update, &p, progress).await
 	});
 	rpc.register_sync("servermsg", |m: ServerMessageParams, c| {
 		if let Err(e) = handle_server_message(&c.log, &c.server_bridges, m) {
@@ -440,17 +446,20 @@ fn make_socket_rpc(
 		let code_server = c.code_server.lock().await.clone();
 		handle_call_server_http(code_server, p).await
 	});
-	rpc.register_async("forward", |p: ForwardParams, c| async move {
+	rpc.register_async_cancellable("forward", |p: ForwardParams, c, token| async move {
 		ensure_auth(&c.auth_state)?;
-		handle_forward(&c.log, &c.port_forwarding, p).await
+		tokio::select! {
+			r = handle_forward(&c.log, &c.port_forwarding, p) => r,
+			_ = token.cancelled() => Err(CodeError::RpcCallCancelled.into()),
+		}
 	});
 	rpc.register_async("unforward", |p: UnforwardParams, c| async move {
 		ensure_auth(&c.auth_state)?;
 		handle_unforward(&c.log, &c.port_forwarding, p).await
 	});
-	rpc.register_async("acquire_cli", |p: AcquireCliParams, c| async move {
+	rpc.register_async_cancellable("acquire_cli", |p: AcquireCliParams, c, token| async move {
 		ensure_auth(&c.auth_state)?;
-		handle_acquire_cli(&c.launcher_paths, &c.http, &c.log, p).await
+		handle_acquire_cli(&c.launcher_paths, &c.http, &c.log, p, token).await
 	});
 	rpc.register_duplex("spawn", 3, |mut streams, p: SpawnParams, c| async move {
 		ensure_auth(&c.auth_state)?;
@@ -505,6 +514,12 @@ fn make_socket_rpc(
 		|_: EmptyObject, _| Ok(VersionResponse::default()),
 	);
 
+	// these wait on the code server or the tunnel service, either of which may
+	// stop answering without closing the connection
+	rpc.set_timeout("callserverhttp", CALL_SERVER_HTTP_TIMEOUT);
+	rpc.set_timeout("forward", PORT_FORWARDING_TIMEOUT);
+	rpc.set_timeout("unforward", PORT_FORWARDING_TIMEOUT);
+
 	rpc.build(log)
 }
 
@@ -658,6 +673,7 @@ async fn handle_socket_read(
 	let mut readhalf = BufReader::new(readhalf);
 	let mut decoder = MsgPackCodec::new();
 	let mut decoder_buf = bytes::BytesMut::new();
+	let connection = rpc.new_connection();
 
 	loop {
 		let read_len = tokio::select! {
@@ -672,7 +688,7 @@ async fn handle_socket_read(
 		rx_counter.fetch_add(read_len, Ordering::Relaxed);
 
 		while let Some(frame) = decoder.decode(&mut decoder_buf)? {
-			match rpc.dispatch_with_partial(&frame.vec, frame.obj) {
+			match rpc.dispatch_with_partial(&connection, &frame.vec, frame.obj) {
 				MaybeSync::Sync(Some(v)) => {
 					if socket_tx.send(SocketSignal::Send(v)).await.is_err() {
 						return Ok(());
@@ -886,6 +902,7 @@ async fn handle_update(
 	log: &log::Logger,
 	did_update: &AtomicBool,
 	params: &UpdateParams,
+	progress: ProgressSender<MsgPackSerializer, UpdateProgress>,
 ) -> Result<UpdateResult, AnyError> {
 	if matches!(is_integrated_cli(), Ok(true)) || did_update.load(Ordering::SeqCst) {
 		return Ok(UpdateResult {
@@ -921,7 +938,7 @@ async fn handle_update(
 	info!(log, "Updating CLI to {}", latest_release);
 
 	let r = updater
-		.do_update(&latest_release, SilentCopyProgress())
+		.do_update(&latest_release, RpcCopyProgress(progress))
 		.await;
 
 	if let Err(e) = r {
@@ -1093,6 +1110,18 @@ fn handle_challenge_verify(
 	}
 }
 
+/// Reports the progress of a download to the client of an rpc call.
+struct RpcCopyProgress(ProgressSender<MsgPackSerializer, UpdateProgress>);
+
+impl ReportCopyProgress for RpcCopyProgress {
+	fn report_progress(&mut self, bytes_so_far: u64, total_bytes: u64) {
+		self.0.send(UpdateProgress {
+			bytes_so_far,
+			total_bytes,
+		});
+	}
+}
+
 async fn handle_forward(
 	log: &log::Logger,
 	port_forwarding: &Option<PortForwarding>,
@@ -1187,25 +1216,35 @@ async fn handle_acquire_cli(
 	http: &Arc<FallbackSimpleHttp>,
 	log: &log::Logger,
 	params: AcquireCliParams,
+	token: CancellationToken,
 ) -> Result<SpawnResult, AnyError> {
 	let update_service = UpdateService::new(log.clone(), http.clone());
 
-	let release = match params.commit_id {
-		Some(commit) => Release {
-			name: format!("{} CLI", PRODUCT_NAME_LONG),
-			commit,
-			platform: params.platform,
-			quality: params.quality,
-			target: TargetKind::Cli,
-		},
-		None => {
-			update_service
-				.get_latest_commit(params.platform, TargetKind::Cli, params.quality)
-				.await?
-		}
+	// only the download is stopped when the call is cancelled; a partial
+	// download is cleaned up by the cache the next time it's requested
+	let download = async {
+		let release = match params.commit_id {
+			Some(commit) => Release {
+				name: format!("{} CLI", PRODUCT_NAME_LONG),
+				commit,
+				platform: params.platform,
+				quality: params.quality,
+				target: TargetKind::Cli,
+			},
+			None => {
+				update_service
+					.get_latest_commit(params.platform, TargetKind::Cli, params.quality)
+					.await?
+			}
+		};
+
+		download_cli_into_cache(&paths.cli_cache, &release, &update_service).await
 	};
 
-	let cli = download_cli_into_cache(&paths.cli_cache, &release, &update_service).await?;
+	let cli = tokio::select! {
+		r = download => r?,
+		_ = token.cancelled() => return Err(CodeError::RpcCallCancelled.into()),
+	};
 	let file = tokio::fs::File::open(cli)
 		.await
 		.map_err(|e| wrap(e, "error opening cli file"))?;
@@ -789,7 +789,7 @@ impl DevTunnels {
 		let mut placeholder_name = Self::get_placeholder_name();
 		if !is_name_free(&placeholder_name) {
 			for i in 2.. {
-				let fixed_name = format!("{}{}", placeholder_name, i);
+				let fixed_name = name_with_suffix(&placeholder_name, i);
 				if is_name_free(&fixed_name) {
 					placeholder_name = fixed_name;
 					break;
@@ -1188,6 +1188,15 @@ fn clean_hostname_for_tunnel(hostname: &str) -> String {
 	}
 }
 
+/// Appends a numeric suffix to the tunnel name, shortening the name if needed
+/// so the result still fits in MAX_TUNNEL_NAME_LENGTH. The name must be ASCII,
+/// which is the case for names from `clean_hostname_for_tunnel`.
+fn name_with_suffix(name: &str, suffix: u32) -> String {
+	let suffix = suffix.to_string();
+	let max_len = MAX_TUNNEL_NAME_LENGTH.saturating_sub(suffix.len());
+	format!("{}{}", &name[..name.len().min(max_len)], suffix)
+}
+
 fn vec_eq_as_set(a: &[String], b: &[String]) -> bool {
 	if a.len() != b.len() {
 		return false;
@@ -1261,4 +1270,18 @@ mod test {
 		);
 		assert_eq!(clean_hostname_for_tunnel("z"), "remote-machine".to_string());
 	}
+
+	#[test]
+	fn test_name_with_suffix() {
+		assert_eq!(name_with_suffix("my-machine", 2), "my-machine2");
+		assert_eq!(
+			name_with_suffix("twenty-chars-machine", 2),
+			"twenty-chars-machin2"
+		);
+		assert_eq!(
+			name_with_suffix("twenty-chars-machine", 10),
+			"twenty-chars-machi10"
+		);
+		assert!(is_valid_name(&name_with_suffix("twenty-chars-machine", 123)).is_ok());
+	}
 }
@@ -110,6 +110,13 @@ pub struct UpdateResult {
 	pub did_update: bool,
 }
 
+/// Progress reported while the `update` call downloads a new CLI.
+#[derive(Serialize, Debug)]
+pub struct UpdateProgress {
+	pub bytes_so_far: u64,
+	pub total_bytes: u64,
+}
+
 #[derive(Serialize, Debug)]
 pub struct ToClientRequest<'a> {
 	pub id: Option<u32>,
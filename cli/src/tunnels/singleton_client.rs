@@ -100,9 +100,8 @@ pub async fn start_singleton_client(args: SingletonClientArgs) -> bool {
 		caller,
 	});
 
-	rpc.register_sync(protocol::singleton::METHOD_SHUTDOWN, |_: EmptyObject, c| {
+	rpc.register_notification(protocol::singleton::METHOD_SHUTDOWN, |_: EmptyObject, c| {
 		c.exit_entirely.store(true, Ordering::SeqCst);
-		Ok(())
 	});
 
 	rpc.register_async(
@@ -134,14 +133,11 @@ pub async fn start_singleton_client(args: SingletonClientArgs) -> bool {
 		},
 	);
 
-	rpc.register_sync(
+	rpc.register_notification(
 		protocol::singleton::METHOD_LOG,
-		|log: protocol::singleton::LogMessageOwned, c| {
-			match log.level {
-				Some(level) => c.log.emit(level, &format!("{}{}", log.prefix, log.message)),
-				None => c.log.result(format!("{}{}", log.prefix, log.message)),
-			}
-			Ok(())
+		|log: protocol::singleton::LogMessageOwned, c| match log.level {
+			Some(level) => c.log.emit(level, &format!("{}{}", log.prefix, log.message)),
+			None => c.log.result(format!("{}{}", log.prefix, log.message)),
 		},
 	);
 
@@ -520,6 +520,8 @@ pub enum CodeError {
 	ServerOriginTimeout,
 	#[error("Server exited without writing port/socket: {0}")]
 	ServerUnexpectedExit(String),
+	#[error("The call was cancelled by the client")]
+	RpcCallCancelled,
 }
 
 makeAnyError!(
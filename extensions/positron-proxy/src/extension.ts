@@ -45,6 +45,22 @@ export function activate(context: vscode.ExtensionContext) {
 		)
 	);
 
+	// Register the positronProxy.startViewerProxyServer command and add its disposable.
+	context.subscriptions.push(
+		vscode.commands.registerCommand(
+			'positronProxy.startViewerProxyServer',
+			async (targetOrigin: string) => await positronProxy.startViewerProxyServer(targetOrigin)
+		)
+	);
+
+	// Register the positronProxy.stopViewerProxyServer command and add its disposable.
+	context.subscriptions.push(
+		vscode.commands.registerCommand(
+			'positronProxy.stopViewerProxyServer',
+			(targetOrigin: string) => positronProxy.stopViewerProxyServer(targetOrigin)
+		)
+	);
+
 	// Register the positronProxy.setHelpProxyServerStyles command and add its disposable.
 	context.subscriptions.push(
 		vscode.commands.registerCommand(
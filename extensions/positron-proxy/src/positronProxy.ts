@@ -8,8 +8,8 @@ import path = require('path');
 import express from 'express';
 import { AddressInfo, Server } from 'net';
 import { ProxyServerStyles } from './extension';
-import { Disposable, ExtensionContext } from 'vscode';
-import { createProxyMiddleware, responseInterceptor } from 'http-proxy-middleware';
+import { Disposable, ExtensionContext, Tunnel, Uri, env, workspace } from 'vscode';
+import { Options, createProxyMiddleware, responseInterceptor } from 'http-proxy-middleware';
 import { HtmlProxyServer } from './htmlProxy';
 
 /**
@@ -65,6 +65,11 @@ export const isAddressInfo = (addressInfo: string | AddressInfo | null): address
  * ProxyServer class.
  */
 export class ProxyServer implements Disposable {
+	/**
+	 * Gets or sets the tunnel that forwards the proxy server's port to the client, if any.
+	 */
+	tunnel?: Tunnel;
+
 	/**
 	 * Constructor.
 	 * @param serverOrigin The server origin.
@@ -78,10 +83,19 @@ export class ProxyServer implements Disposable {
 	) {
 	}
 
+	/**
+	 * Gets the port the proxy server is listening on.
+	 */
+	get port(): number {
+		const address = this.server.address();
+		return isAddressInfo(address) ? address.port : 0;
+	}
+
 	/**
 	 * Disposes of the ProxyServer.
 	 */
 	dispose(): void {
+		this.tunnel?.dispose();
 		this.server.close();
 	}
 }
@@ -122,6 +136,12 @@ export class PositronProxy implements Disposable {
 	 */
 	private _proxyServers = new Map<string, ProxyServer>();
 
+	/**
+	 * Gets or sets the viewer proxy servers, keyed by target origin. These are kept apart from
+	 * the help proxy servers so that viewer content is never served by a help proxy server.
+	 */
+	private _viewerProxyServers = new Map<string, ProxyServer>();
+
 	/**
 	 * The HTML proxy server. There's only ever one of these; it serves all raw
 	 * HTML content.
@@ -166,6 +186,9 @@ export class PositronProxy implements Disposable {
 		this._proxyServers.forEach(proxyServer => {
 			proxyServer.dispose();
 		});
+		this._viewerProxyServers.forEach(proxyServer => {
+			proxyServer.dispose();
+		});
 		if (this._htmlProxyServer) {
 			this._htmlProxyServer.dispose();
 		}
@@ -183,6 +206,7 @@ export class PositronProxy implements Disposable {
 	startHelpProxyServer(targetOrigin: string): Promise<string> {
 		// Start the proxy server.
 		return this.startProxyServer(
+			this._proxyServers,
 			targetOrigin,
 			async (serverOrigin, url, contentType, responseBuffer) => {
 				// If this isn't 'text/html' content, just return the response buffer.
@@ -226,6 +250,83 @@ export class PositronProxy implements Disposable {
 			});
 	}
 
+	/**
+	 * Starts a viewer proxy server for content served on a local port, such as an htmlwidget or a
+	 * Shiny app.
+	 * @param targetOrigin The target origin.
+	 * @returns The externally reachable server origin.
+	 */
+	async startViewerProxyServer(targetOrigin: string): Promise<string> {
+		// Start the proxy server. Viewer content is passed through unchanged, so responses are
+		// streamed rather than buffered.
+		const serverOrigin = await this.startProxyServer(
+			this._viewerProxyServers,
+			targetOrigin,
+			undefined,
+			{
+				// Rewrite redirects to the target origin so they stay on the proxy server.
+				autoRewrite: true,
+				// Proxy websockets too, as apps like Shiny depend on them.
+				ws: true,
+			}
+		);
+
+		// When the extension host is local, the client can reach the proxy server directly.
+		if (!env.remoteName) {
+			const externalUri = await env.asExternalUri(Uri.parse(serverOrigin));
+			return externalUri.toString(true);
+		}
+
+		// Otherwise, the proxy server's port must be forwarded before the client can reach it.
+		// Open the tunnel here, rather than through env.asExternalUri, so that it can be closed
+		// when the proxy server is stopped.
+		const proxyServer = this._viewerProxyServers.get(targetOrigin);
+		if (proxyServer && !proxyServer.tunnel) {
+			const tunnel = await workspace.openTunnel({
+				remoteAddress: { host: HOST, port: proxyServer.port }
+			});
+
+			// If the proxy server was stopped while the tunnel was opening, close the tunnel.
+			if (this._viewerProxyServers.get(targetOrigin) !== proxyServer) {
+				tunnel.dispose();
+				throw new Error(`The viewer proxy server for ${targetOrigin} was stopped.`);
+			}
+			proxyServer.tunnel = tunnel;
+		}
+
+		// Return the local address of the tunnel.
+		const localAddress = proxyServer?.tunnel?.localAddress;
+		if (localAddress === undefined) {
+			return serverOrigin;
+		} else if (typeof localAddress === 'string') {
+			return localAddress.includes('://') ? localAddress : `http://${localAddress}`;
+		} else {
+			return `http://${localAddress.host}:${localAddress.port}`;
+		}
+	}
+
+	/**
+	 * Stops a viewer proxy server, closing the tunnel that forwards its port, if any.
+	 * @param targetOrigin The target origin.
+	 * @returns A value which indicates whether the proxy server for the target origin was found and
+	 * stopped.
+	 */
+	stopViewerProxyServer(targetOrigin: string): boolean {
+		// See if we have a viewer proxy server for the target origin. If we do, stop it.
+		const proxyServer = this._viewerProxyServers.get(targetOrigin);
+		if (proxyServer) {
+			// Remove and stop the proxy server.
+			this._viewerProxyServers.delete(targetOrigin);
+			proxyServer.dispose();
+
+			// A proxy server for the target origin was found and stopped.
+			return true;
+		}
+
+		// A proxy server for the target origin was not found.
+		return false;
+	}
+
 	/**
 	 * Stops a help proxy server.
 	 * @param targetOrigin The target origin.
@@ -275,16 +376,23 @@ export class PositronProxy implements Disposable {
 
 	/**
 	 * Starts a proxy server.
+	 * @param proxyServers The proxy servers to add the proxy server to.
 	 * @param targetOrigin The target origin.
-	 * @param contentRewriter The content rewriter/
+	 * @param contentRewriter The content rewriter, or undefined to pass content through unchanged.
+	 * @param proxyOptions Additional options for the proxy middleware.
 	 * @returns The server origin.
 	 */
-	startProxyServer(targetOrigin: string, contentRewriter: ContentRewriter): Promise<string> {
+	startProxyServer(
+		proxyServers: Map<string, ProxyServer>,
+		targetOrigin: string,
+		contentRewriter: ContentRewriter | undefined,
+		proxyOptions: Options = {}
+	): Promise<string> {
 		// Return a promise.
 		return new Promise((resolve, reject) => {
 			// See if we have an existing proxy server for target origin. If there is, return the
 			// server origin.
-			const proxyServer = this._proxyServers.get(targetOrigin);
+			const proxyServer = proxyServers.get(targetOrigin);
 			if (proxyServer) {
 				resolve(proxyServer.serverOrigin);
 				return;
@@ -307,34 +415,23 @@ export class PositronProxy implements Disposable {
 				const serverOrigin = `http://${address.address}:${address.port}`;
 
 				// Add the proxy server.
-				this._proxyServers.set(targetOrigin, new ProxyServer(
+				proxyServers.set(targetOrigin, new ProxyServer(
 					serverOrigin,
 					targetOrigin,
 					server
 				));
 
-				// Add the proxy midleware.
+				// Add the proxy midleware. Responses are only intercepted when there's a content
+				// rewriter, since intercepting a response buffers it in full.
 				app.use('*', createProxyMiddleware({
+					...proxyOptions,
 					target: targetOrigin,
 					changeOrigin: true,
-					selfHandleResponse: true,
 					// Logging for development work.
 					// onProxyReq: (proxyReq, req, res, options) => {
 					// 	console.log(`Proxy request ${serverOrigin}${req.url} -> ${targetOrigin}${req.url}`);
 					// },
-					onProxyRes: responseInterceptor(async (responseBuffer, proxyRes, req, res) => {
-						// Get the URL and the content type. These must be present to call the
-						// content rewriter. Also, the scripts must be loaded.
-						const url = req.url;
-						const contentType = proxyRes.headers['content-type'];
-						if (!url || !contentType || !this._scriptsFileLoaded) {
-							// Don't process the response.
-							return responseBuffer;
-						}
-
-						// Rewrite the content.
-						return contentRewriter(serverOrigin, url, contentType, responseBuffer);
-					})
+					...(contentRewriter ? this.rewritingProxyOptions(serverOrigin, contentRewriter) : {})
 				}));
 
 				// Return the server origin.
@@ -343,5 +440,30 @@ export class PositronProxy implements Disposable {
 		});
 	}
 
+	/**
+	 * Gets the proxy middleware options that rewrite the content of responses.
+	 * @param serverOrigin The server origin.
+	 * @param contentRewriter The content rewriter.
+	 * @returns The proxy middleware options.
+	 */
+	private rewritingProxyOptions(serverOrigin: string, contentRewriter: ContentRewriter): Options {
+		return {
+			selfHandleResponse: true,
+			onProxyRes: responseInterceptor(async (responseBuffer, proxyRes, req, res) => {
+				// Get the URL and the content type. These must be present to call the
+				// content rewriter. Also, the scripts must be loaded.
+				const url = req.url;
+				const contentType = proxyRes.headers['content-type'];
+				if (!url || !contentType || !this._scriptsFileLoaded) {
+					// Don't process the response.
+					return responseBuffer;
+				}
+
+				// Rewrite the content.
+				return contentRewriter(serverOrigin, url, contentType, responseBuffer);
+			})
+		};
+	}
+
 	//#endregion Private Methods
 }
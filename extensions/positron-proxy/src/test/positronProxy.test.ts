@@ -0,0 +1,120 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (C) 2024 Posit Software, PBC. All rights reserved.
+ *  Licensed under the Elastic License 2.0. See LICENSE.txt for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+import * as assert from 'assert';
+import * as http from 'http';
+import * as vscode from 'vscode';
+import { isAddressInfo } from '../positronProxy';
+
+/**
+ * Starts a target server on a random port.
+ * @param listener The request listener.
+ * @returns The server.
+ */
+function startTargetServer(listener: http.RequestListener): Promise<http.Server> {
+	return new Promise((resolve, reject) => {
+		const server = http.createServer(listener);
+		server.on('error', reject);
+		server.listen(0, 'localhost', () => resolve(server));
+	});
+}
+
+/**
+ * Gets the first chunk of the body of a response.
+ * @param url The URL.
+ * @returns The first chunk of the body.
+ */
+function getFirstChunk(url: string): Promise<string> {
+	return new Promise((resolve, reject) => {
+		http.get(url, response => {
+			response.once('data', chunk => {
+				resolve(chunk.toString());
+				response.destroy();
+			});
+		}).on('error', reject);
+	});
+}
+
+/**
+ * Joins a server origin, which may end with a slash, and a path.
+ */
+const joinUrl = (origin: string, path: string) => `${origin.replace(/\/$/, '')}${path}`;
+
+suite('Proxy servers', () => {
+	let target: http.Server;
+	let targetOrigin: string;
+	let releaseStream: () => void;
+
+	setup(async () => {
+		const streamReleased = new Promise<void>(resolve => {
+			releaseStream = resolve;
+		});
+		target = await startTargetServer((request, response) => {
+			if (request.url === '/stream') {
+				// Send the first event, then hold the response open until the test is done.
+				response.writeHead(200, { 'content-type': 'text/event-stream' });
+				response.write('data: 1\n\n');
+				streamReleased.then(() => response.end());
+			} else {
+				response.writeHead(200, { 'content-type': 'text/plain' });
+				response.end('hello');
+			}
+		});
+
+		const address = target.address();
+		assert.ok(isAddressInfo(address));
+		targetOrigin = `http://localhost:${address.port}`;
+	});
+
+	teardown(async () => {
+		releaseStream();
+		await vscode.commands.executeCommand('positronProxy.stopHelpProxyServer', targetOrigin);
+		await vscode.commands.executeCommand('positronProxy.stopViewerProxyServer', targetOrigin);
+		target.close();
+	});
+
+	test('Help and viewer proxy servers for the same target are kept apart', async () => {
+		const helpOrigin = await vscode.commands.executeCommand<string>(
+			'positronProxy.startHelpProxyServer',
+			targetOrigin
+		);
+		const viewerOrigin = await vscode.commands.executeCommand<string>(
+			'positronProxy.startViewerProxyServer',
+			targetOrigin
+		);
+		assert.notStrictEqual(viewerOrigin, helpOrigin);
+
+		// Starting either proxy server again returns the existing one.
+		assert.strictEqual(
+			await vscode.commands.executeCommand('positronProxy.startHelpProxyServer', targetOrigin),
+			helpOrigin
+		);
+		assert.strictEqual(
+			await vscode.commands.executeCommand('positronProxy.startViewerProxyServer', targetOrigin),
+			viewerOrigin
+		);
+
+		// Stopping the viewer proxy server leaves the help proxy server running.
+		assert.strictEqual(
+			await vscode.commands.executeCommand('positronProxy.stopViewerProxyServer', targetOrigin),
+			true
+		);
+		assert.strictEqual(
+			await vscode.commands.executeCommand('positronProxy.stopViewerProxyServer', targetOrigin),
+			false
+		);
+		assert.strictEqual(await getFirstChunk(joinUrl(helpOrigin, '/')), 'hello');
+	});
+
+	test('Viewer proxy server streams responses', async () => {
+		const viewerOrigin = await vscode.commands.executeCommand<string>(
+			'positronProxy.startViewerProxyServer',
+			targetOrigin
+		);
+
+		// The first event arrives while the target is still holding the response open.
+		assert.strictEqual(await getFirstChunk(joinUrl(viewerOrigin, '/stream')), 'data: 1\n\n');
+	});
+});
@@ -58,6 +58,12 @@ echo
 yarn test-extension -l positron-connections
 kill_app
 
+echo
+echo "### Positron Proxy tests"
+echo
+yarn test-extension -l positron-proxy
+kill_app
+
 # Cleanup
 
 rm -rf $VSCODEUSERDATADIR
@@ -139,6 +139,12 @@ echo
 yarn test-extension -l positron-connections
 kill_app
 
+echo
+echo "### Positron Proxy tests"
+echo
+yarn test-extension -l positron-proxy
+kill_app
+
 # --- End Positron ---
 
 # Tests standalone (CommonJS)